@@ -1,41 +1,60 @@
 extern crate docopt;
 extern crate fnv;
+extern crate rand;
 extern crate rusoto_core;
 extern crate rusoto_sqs;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
 #[macro_use]
 extern crate serde_json;
+extern crate uuid;
+
+mod spool;
 
 use docopt::Docopt;
 use docopt::Value::Plain;
 use fnv::FnvHashMap;
-use rusoto_core::Region;
+use rand::Rng;
+use rusoto_core::{Region, RusotoError};
 use rusoto_sqs::{
-    DeleteMessageRequest,
+    DeleteMessageBatchRequest,
+    DeleteMessageBatchRequestEntry,
     GetQueueAttributesRequest,
     GetQueueAttributesResult,
     GetQueueUrlError,
     GetQueueUrlRequest,
     Message,
+    MessageAttributeValue,
     ReceiveMessageRequest,
-    SendMessageRequest,
+    SendMessageBatchRequest,
+    SendMessageBatchRequestEntry,
+    SendMessageBatchResultEntry,
     SqsClient,
     Sqs
 };
-use std::collections::HashMap;
+use spool::{Spool, SpoolEntry};
+use std::collections::{HashMap, VecDeque};
 use std::cmp;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::sync_channel;
+use std::thread;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
 
 const USAGE: &'static str = "
 Simple SQS queue reader. Automatically retries and deduplicates until the
 desired number of messages have been read.
 
-Can either output messages to stdout or transfer them to another queue.
-
-NOTE: transferring message attributes is currently not supported, and thus
-custom attributes will not be preserved when moving messages.
+Can either output messages to stdout or transfer them to one or more other
+queues. When multiple output queues are given, every message is broadcast
+to all of them.
 
 Usage:
-    sqs-reader <in-queue> [--stdout] (--all|[--count=<n>]) [--block] [--drain] [--full]
-    sqs-reader <in-queue> <out-queue> [--stdout] (--all|[--count=<n>]) [--block] [--drain] [--full]
+    sqs-reader <in-queue> [--stdout] (--all|[--count=<n>]) [--block] [--drain] [--full] [--concurrency=<n>] [--spool=<dir>] [--max-retries=<n>]
+    sqs-reader <in-queue> <out-queue>... [--stdout] (--all|[--count=<n>]) [--block] [--drain] [--full] [--concurrency=<n>] [--spool=<dir>] [--max-retries=<n>]
     sqs-reader -h | --help
 
 Options:
@@ -52,8 +71,34 @@ Options:
   --drain       Remove messages from queue after all have been read.
   --full        Print full response with message attributes instead of just
                 printing the message body.
+  --concurrency=<n>  Number of concurrent receiver and sender/deleter workers
+                to run [default: 4]. Higher values trade holding more
+                in-flight (invisible) messages at once for higher throughput.
+  --spool=<dir> Write a durable, fsync'd record of each message to this
+                directory before deleting it from the input queue, so a
+                crash mid-transfer can be resumed instead of losing the
+                message. Delivery is at-least-once, not exactly-once:
+                replay resends messages that were already delivered
+                before the crash. Requires an output queue to replay
+                into. Only meaningful alongside --drain.
+  --max-retries=<n>  Number of times to retry a receive/send/delete call
+                that fails with a retryable (throttling or transient
+                service) error, using exponential backoff with jitter
+                [default: 5].
 ";
 
+// SQS batch APIs cap every batch request at 10 entries.
+const BATCH_LIMIT: usize = 10;
+
+// Backoff for retried SQS calls: base * 2^attempt, capped, with full jitter.
+const RETRY_BASE_DELAY_MS: u64 = 100;
+const RETRY_MAX_DELAY_MS: u64 = 10_000;
+
+// Visibility timeout used before we have any observed processing latency.
+const DEFAULT_VISIBILITY_TIMEOUT_SECS: i64 = 60;
+
+// How many recent batch-processing latencies to keep for the p95 estimate.
+const LATENCY_WINDOW: usize = 200;
 
 fn main () {
     let args = Docopt::new(USAGE)
@@ -67,23 +112,39 @@ fn main () {
         .expect(&format!("fetching input queue url for {}", &in_queue));
 
     let stdout = args.get_bool("--stdout");
-    let out_queue = args.find("<out-queue>").and_then(|value|
-        if let Plain(Some(name)) = value { Some(name) } else { None }
-    );
-    let out_url : Option<String> = out_queue.map(|name|
-        get_queue_url(&sqs, &name.as_str().to_string())
-            .expect(&format!("fetching output queue url for {}", &name.as_str()))
-    );
+    let out_urls : Vec<String> = args.get_vec("<out-queue>").iter().map(|name|
+        get_queue_url(&sqs, &name.to_string())
+            .expect(&format!("fetching output queue url for {}", name))
+    ).collect();
 
-    if !stdout && !out_url.is_some() {
+    if !stdout && out_urls.is_empty() {
         panic!("Either --stdout or an output queue name must be provided");
     }
 
+    let spool_dir = args.find("--spool").and_then(|value|
+        if let Plain(Some(dir)) = value { Some(dir.to_owned()) } else { None }
+    );
+
+    if spool_dir.is_some() && out_urls.is_empty() {
+        panic!("--spool requires an output queue to replay into");
+    }
+
+    let spool = spool_dir.map(|dir| Arc::new(Spool::open(Path::new(&dir))));
+
     let drain = args.get_bool("--drain");
     let all = args.get_bool("--all");
     let block = args.get_bool("--block");
+    let full = args.get_bool("--full");
 
-    let mut all_messages = FnvHashMap::default();
+    let concurrency: usize = args.get_str("--concurrency").parse()
+        .expect("Could not parse --concurrency");
+
+    let max_retries: u32 = args.get_str("--max-retries").parse()
+        .expect("Could not parse --max-retries");
+
+    if let Some(spool) = &spool {
+        replay_spool(&sqs, &in_url, &out_urls, spool, max_retries);
+    }
 
     let total = get_approximate_queue_size(&sqs, &in_url)
         .expect("Could not get approximate input queue size");
@@ -100,57 +161,276 @@ fn main () {
 
     let mut attribute_names = vec!("All".to_owned());
     attribute_names.resize(1, "All".to_owned());
-    while all_messages.len() < count as usize {
-        let response = sqs.receive_message(ReceiveMessageRequest {
-            attribute_names: Some(attribute_names.clone()),
-            max_number_of_messages: Some(1),
-            message_attribute_names: None,
-            queue_url: in_url.to_string(),
-            receive_request_attempt_id: None,
-            visibility_timeout: Some(if drain { 60 } else { 0 }),
-            wait_time_seconds: None
-        }).sync().expect("reading from queue");
-
-        let current_count = if let Some(messages) = response.messages {
-            let len = messages.len();
-            for message in messages {
-                let id = message.message_id.to_owned().expect("getting id");
-                all_messages.insert(id, message);
+
+    // Bounded at `concurrency * BATCH_LIMIT` queued messages, so receivers
+    // block once the workers fall behind. Each receiver's current batch and
+    // each worker's current chunk (up to BATCH_LIMIT apiece) are in flight
+    // on top of what the channel holds.
+    let (sender, receiver) = sync_channel::<Message>(concurrency * BATCH_LIMIT);
+    let receiver = Arc::new(Mutex::new(receiver));
+    let seen = Arc::new(Mutex::new(FnvHashMap::default()));
+    let received_count = Arc::new(AtomicUsize::new(0));
+    let stop = Arc::new(AtomicBool::new(false));
+
+    // Recent batch-processing latencies, used to set the next receive's
+    // visibility timeout instead of a fixed guess.
+    let latencies: Arc<Mutex<VecDeque<Duration>>> = Arc::new(Mutex::new(VecDeque::new()));
+
+    let receiver_handles: Vec<_> = (0..concurrency).map(|_| {
+        let sqs = sqs.clone();
+        let in_url = in_url.clone();
+        let sender = sender.clone();
+        let seen = seen.clone();
+        let received_count = received_count.clone();
+        let stop = stop.clone();
+        let attribute_names = attribute_names.clone();
+        let latencies = latencies.clone();
+
+        thread::spawn(move || {
+            while received_count.load(Ordering::SeqCst) < count as usize
+                && !stop.load(Ordering::SeqCst) {
+                let visibility_timeout = if drain { visibility_timeout_secs(&latencies) } else { 0 };
+                let response = with_retries(max_retries, || sqs.receive_message(ReceiveMessageRequest {
+                    attribute_names: Some(attribute_names.clone()),
+                    max_number_of_messages: Some(10),
+                    message_attribute_names: Some(vec!("All".to_owned())),
+                    queue_url: in_url.to_string(),
+                    receive_request_attempt_id: None,
+                    visibility_timeout: Some(visibility_timeout),
+                    wait_time_seconds: None
+                }).sync());
+
+                let messages = response.messages.unwrap_or_default();
+                let current_count = messages.len();
+
+                for message in messages {
+                    let id = message.message_id.to_owned().expect("getting id");
+                    let is_new = {
+                        let mut seen = seen.lock().unwrap();
+                        if seen.contains_key(&id) {
+                            false
+                        } else {
+                            seen.insert(id, ());
+                            true
+                        }
+                    };
+
+                    if !is_new {
+                        continue;
+                    }
+
+                    // Reserve a slot via CAS before enqueuing, so concurrent
+                    // receivers can't all observe room under `count` and
+                    // collectively overshoot it.
+                    let reserved = loop {
+                        let current = received_count.load(Ordering::SeqCst);
+                        if current >= count as usize {
+                            break false;
+                        }
+                        if received_count.compare_exchange(
+                            current, current + 1, Ordering::SeqCst, Ordering::SeqCst
+                        ).is_ok() {
+                            break true;
+                        }
+                    };
+
+                    if !reserved {
+                        // Cap already reached (by this or another thread); stop here.
+                        stop.store(true, Ordering::SeqCst);
+                        break;
+                    }
+
+                    if sender.send(message).is_err() {
+                        return;
+                    }
+                }
+
+                if !block && current_count == 0 {
+                    stop.store(true, Ordering::SeqCst);
+                }
+            }
+        })
+    }).collect();
+
+    // Disconnect the channel once every receiver thread has exited.
+    drop(sender);
+
+    let worker_handles: Vec<_> = (0..concurrency).map(|_| {
+        let sqs = sqs.clone();
+        let in_url = in_url.clone();
+        let out_urls = out_urls.clone();
+        let receiver = receiver.clone();
+        let spool = spool.clone();
+        let latencies = latencies.clone();
+
+        thread::spawn(move || {
+            loop {
+                let chunk = {
+                    let receiver = receiver.lock().unwrap();
+                    let mut chunk = match receiver.recv() {
+                        Ok(message) => vec!(message),
+                        Err(_) => return
+                    };
+
+                    while chunk.len() < BATCH_LIMIT {
+                        match receiver.try_recv() {
+                            Ok(message) => chunk.push(message),
+                            Err(_) => break
+                        }
+                    }
+
+                    chunk
+                };
+
+                let started = Instant::now();
+                process_batch(&sqs, &in_url, &out_urls, stdout, full, drain, &spool, max_retries, chunk);
+                record_latency(&latencies, started.elapsed());
+            }
+        })
+    }).collect();
+
+    for handle in receiver_handles {
+        handle.join().expect("receiver worker panicked");
+    }
+
+    for handle in worker_handles {
+        handle.join().expect("sender/deleter worker panicked");
+    }
+}
+
+fn process_batch (sqs: &SqsClient, in_url: &String, out_urls: &Vec<String>, stdout: bool, full: bool, drain: bool, spool: &Option<Arc<Spool>>, max_retries: u32, chunk: Vec<Message>) {
+    if stdout {
+        for message in &chunk {
+            if full {
+                print_full_message(message.clone());
+            } else {
+                println!("{}", message.body.to_owned().expect("getting body"));
             }
-            len
-        } else { 0 };
+        }
+    }
 
+    // Broadcast to every destination queue before the delete below.
+    for url in out_urls {
+        let entries = chunk.iter()
+            .map(|message| (Uuid::new_v4().to_string(), message.to_owned()))
+            .collect();
+        let sent = send_message_batch(sqs, url, entries, max_retries);
+
+        for result in sent.values() {
+            let value = json!({
+                "QueueUrl": url,
+                "MD5OfMessageBody": result.md5_of_message_body,
+                "MessageId": result.message_id,
+            });
+            println!("{}", value.to_string());
+        }
+    }
 
-        if !block && current_count == 0 {
-            break
+    // Only purge messages after every destination has acknowledged them.
+    if drain {
+        // Spool each message before deleting, so a crash in between can
+        // be replayed on the next startup.
+        let spool_indices: Vec<u64> = match spool {
+            Some(spool) => chunk.iter().map(|message| spool.append(message)).collect(),
+            None => Vec::new()
+        };
+
+        let entries = chunk.iter()
+            .map(|message| (
+                Uuid::new_v4().to_string(),
+                message.receipt_handle.to_owned().expect("getting receipt handle")
+            ))
+            .collect();
+        delete_message_batch(sqs, in_url, entries, max_retries);
+
+        if let Some(spool) = spool {
+            for index in spool_indices {
+                spool.ack(index);
+            }
         }
     }
+}
 
-    for (_id, message) in all_messages {
-        let body = message.body.to_owned().expect("getting body");
+// Replays spool entries left un-acknowledged by a previous run. Entries are
+// spooled only after process_batch has sent them to every output queue, so
+// replay sends each of them again: --spool is at-least-once, not
+// exactly-once.
+fn replay_spool (sqs: &SqsClient, in_url: &String, out_urls: &Vec<String>, spool: &Spool, max_retries: u32) {
+    let pending = spool.pending_entries();
 
-        if stdout {
-            if args.get_bool("--full") {
-                print_full_message(message.clone());
+    if pending.is_empty() {
+        return;
+    }
+
+    eprintln!("replaying {} un-acknowledged spool entries", pending.len());
+
+    for chunk in pending.chunks(BATCH_LIMIT) {
+        for url in out_urls {
+            let send_entries = chunk.iter()
+                .map(|entry| (Uuid::new_v4().to_string(), spool::to_message(entry)))
+                .collect();
+            send_message_batch(sqs, url, send_entries, max_retries);
+        }
+
+        delete_replayed_entries(sqs, in_url, chunk, spool, max_retries);
+    }
+}
+
+// SQS's failure code for a delete against an expired or superseded handle.
+const STALE_RECEIPT_HANDLE_CODE: &str = "ReceiptHandleIsInvalid";
+
+// Deletes replayed spool entries, acknowledging each as its delete
+// succeeds. A stale receipt handle can mean the delete already happened
+// before the crash, or never happened at all -- indistinguishable from
+// the error alone -- so either way we give up on it and acknowledge the
+// entry rather than retrying a delete that can't succeed.
+fn delete_replayed_entries (sqs: &SqsClient, url: &String, chunk: &[SpoolEntry], spool: &Spool, max_retries: u32) {
+    let mut entries: HashMap<String, SpoolEntry> = chunk.iter()
+        .map(|entry| (Uuid::new_v4().to_string(), entry.to_owned()))
+        .collect();
+    let mut attempt = 0;
+
+    loop {
+        attempt += 1;
+
+        let request_entries: Vec<DeleteMessageBatchRequestEntry> = entries.iter().map(|(id, entry)|
+            DeleteMessageBatchRequestEntry {
+                id: id.to_owned(),
+                receipt_handle: entry.receipt_handle.to_owned()
+            }
+        ).collect();
+
+        let response = with_retries(max_retries, || sqs.delete_message_batch(DeleteMessageBatchRequest {
+            entries: request_entries.clone(),
+            queue_url: url.to_string()
+        }).sync());
+
+        for success in response.successful {
+            if let Some(entry) = entries.remove(&success.id) {
+                spool.ack(entry.index);
+            }
+        }
+
+        for failure in response.failed {
+            if failure.code == STALE_RECEIPT_HANDLE_CODE {
+                eprintln!("giving up on replayed spool entry {} with stale receipt handle", failure.id);
+                if let Some(entry) = entries.remove(&failure.id) {
+                    spool.ack(entry.index);
+                }
             } else {
-                println!("{}", body);
+                eprintln!("retrying failed delete for replayed spool entry {}: {}", failure.id, failure.message.unwrap_or_default());
             }
         }
 
-        if let Some(url) = &out_url {
-            let response = send_message(&sqs, &url, body);
-            println!("{}", response);
+        if entries.is_empty() {
+            return;
         }
 
-        // Only purge the message after it has been properly handled. This
-        // avoids any possibility of data loss.
-        if drain {
-            let handle = message.receipt_handle.to_owned();
-            sqs.delete_message(DeleteMessageRequest {
-                queue_url: in_url.to_string(),
-                receipt_handle: handle.expect("getting receipt handle")
-            }).sync().unwrap();
+        if attempt > max_retries {
+            panic!("giving up on delete_replayed_entries after {} attempts, {} entries still failing", attempt, entries.len());
         }
+
+        thread::sleep(backoff_delay(attempt));
     }
 }
 
@@ -177,31 +457,185 @@ fn get_approximate_queue_size (sqs: &SqsClient, url: &String) -> Result<u32, &'s
 
 fn print_full_message (message: Message) {
     let attributes = message.attributes.unwrap_or(HashMap::new());
+    let message_attributes = message.message_attributes.unwrap_or(HashMap::new());
     let value = json!({
         "Body": message.body.expect("getting body"),
         "ReceiptHandle": message.receipt_handle.expect("getting receipt handle"),
         "MD5OfBody": message.md5_of_body.expect("getting md5 of body"),
         "MessageId": message.message_id.expect("getting message id"),
         "Attributes": attributes,
+        "MessageAttributes": message_attributes_to_json(&message_attributes),
     });
 
     println!("{}", value.to_string());
 }
 
-fn send_message (sqs: &SqsClient, url: &String, body: String) -> String {
-    let response = sqs.send_message(SendMessageRequest {
-        delay_seconds: None,
-        message_attributes: None,
-        message_body: body,
-        message_deduplication_id: None,
-        message_group_id: None,
-        queue_url: url.to_string()
-    }).sync().expect("sending message");
+fn message_attributes_to_json (attributes: &HashMap<String, MessageAttributeValue>) -> serde_json::Value {
+    let mut map = serde_json::Map::new();
+    for (name, value) in attributes {
+        map.insert(name.to_owned(), json!({
+            "DataType": value.data_type,
+            "StringValue": value.string_value,
+        }));
+    }
+    serde_json::Value::Object(map)
+}
+
+// Sends up to BATCH_LIMIT messages, retrying just the failed entries
+// (with backoff) until they succeed or `max_retries` is exhausted.
+fn send_message_batch (sqs: &SqsClient, url: &String, mut entries: HashMap<String, Message>, max_retries: u32) -> HashMap<String, SendMessageBatchResultEntry> {
+    let mut results = HashMap::new();
+    let mut attempt = 0;
+
+    loop {
+        attempt += 1;
+
+        let request_entries: Vec<SendMessageBatchRequestEntry> = entries.iter().map(|(id, message)|
+            SendMessageBatchRequestEntry {
+                id: id.to_owned(),
+                message_body: message.body.to_owned().expect("getting body"),
+                delay_seconds: None,
+                message_attributes: message.message_attributes.to_owned(),
+                message_deduplication_id: None,
+                message_group_id: None
+            }
+        ).collect();
 
-    let value = json!({
-        "MD5OfMessageBody": response.md5_of_message_body.expect("getting md5 of body"),
-        "MessageId": response.message_id.expect("getting message id"),
-    });
+        let response = with_retries(max_retries, || sqs.send_message_batch(SendMessageBatchRequest {
+            entries: request_entries.clone(),
+            queue_url: url.to_string()
+        }).sync());
+
+        for success in response.successful {
+            entries.remove(&success.id);
+            results.insert(success.id.to_owned(), success);
+        }
+
+        for failure in response.failed {
+            eprintln!("retrying failed send for batch entry {}: {}", failure.id, failure.message.unwrap_or_default());
+        }
+
+        if entries.is_empty() {
+            return results;
+        }
+
+        if attempt > max_retries {
+            panic!("giving up on send_message_batch after {} attempts, {} entries still failing", attempt, entries.len());
+        }
+
+        thread::sleep(backoff_delay(attempt));
+    }
+}
+
+// Deletes up to BATCH_LIMIT messages, retrying just the failed entries
+// (with backoff) until they succeed or `max_retries` is exhausted.
+fn delete_message_batch (sqs: &SqsClient, url: &String, mut entries: HashMap<String, String>, max_retries: u32) {
+    let mut attempt = 0;
+
+    loop {
+        attempt += 1;
+
+        let request_entries: Vec<DeleteMessageBatchRequestEntry> = entries.iter().map(|(id, receipt_handle)|
+            DeleteMessageBatchRequestEntry {
+                id: id.to_owned(),
+                receipt_handle: receipt_handle.to_owned()
+            }
+        ).collect();
+
+        let response = with_retries(max_retries, || sqs.delete_message_batch(DeleteMessageBatchRequest {
+            entries: request_entries.clone(),
+            queue_url: url.to_string()
+        }).sync());
+
+        for success in response.successful {
+            entries.remove(&success.id);
+        }
+
+        for failure in response.failed {
+            eprintln!("retrying failed delete for batch entry {}: {}", failure.id, failure.message.unwrap_or_default());
+        }
+
+        if entries.is_empty() {
+            return;
+        }
+
+        if attempt > max_retries {
+            panic!("giving up on delete_message_batch after {} attempts, {} entries still failing", attempt, entries.len());
+        }
+
+        thread::sleep(backoff_delay(attempt));
+    }
+}
+
+// Retries a rusoto call on a retryable error, with backoff; anything else
+// (or exhausting `max_retries`) is fatal, like the rest of this tool's AWS calls.
+fn with_retries<T, E, F> (max_retries: u32, mut call: F) -> T
+where
+    E: std::fmt::Debug,
+    F: FnMut() -> Result<T, RusotoError<E>>
+{
+    let mut attempt = 0;
+
+    loop {
+        match call() {
+            Ok(value) => return value,
+            Err(error) => {
+                attempt += 1;
+                if attempt > max_retries || !is_retryable(&error) {
+                    panic!("giving up after {} attempts: {:?}", attempt, error);
+                }
+
+                let delay = backoff_delay(attempt);
+                eprintln!("retrying after {:?} due to {:?}", delay, error);
+                thread::sleep(delay);
+            }
+        }
+    }
+}
+
+fn is_retryable<E: std::fmt::Debug> (error: &RusotoError<E>) -> bool {
+    match error {
+        RusotoError::HttpDispatch(_) => true,
+        RusotoError::Unknown(response) =>
+            response.status.as_u16() == 429 || response.status.is_server_error(),
+        RusotoError::Service(inner) => {
+            let message = format!("{:?}", inner);
+            message.contains("Throttling") || message.contains("ServiceUnavailable")
+        },
+        _ => false
+    }
+}
+
+fn backoff_delay (attempt: u32) -> Duration {
+    let exponent = cmp::min(attempt, 10);
+    let cap_ms = cmp::min(RETRY_MAX_DELAY_MS, RETRY_BASE_DELAY_MS * 2u64.pow(exponent));
+    let jitter_ms = rand::thread_rng().gen_range(0, cap_ms + 1);
+    Duration::from_millis(jitter_ms)
+}
+
+fn record_latency (latencies: &Mutex<VecDeque<Duration>>, duration: Duration) {
+    let mut latencies = latencies.lock().unwrap();
+    latencies.push_back(duration);
+    if latencies.len() > LATENCY_WINDOW {
+        latencies.pop_front();
+    }
+}
+
+// p95 of recent batch-processing latencies.
+fn visibility_timeout_secs (latencies: &Mutex<VecDeque<Duration>>) -> i64 {
+    let latencies = latencies.lock().unwrap();
+
+    if latencies.is_empty() {
+        return DEFAULT_VISIBILITY_TIMEOUT_SECS;
+    }
+
+    let mut samples: Vec<Duration> = latencies.iter().cloned().collect();
+    samples.sort();
+
+    let index = cmp::min(
+        ((samples.len() as f64) * 0.95).ceil() as usize,
+        samples.len()
+    ) - 1;
 
-    value.to_string()
+    cmp::max(1, samples[index].as_secs() as i64 + 1)
 }
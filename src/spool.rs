@@ -0,0 +1,192 @@
+use rusoto_sqs::{Message, MessageAttributeValue};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+const SEGMENT_FILE_NAME: &str = "segment.jsonl";
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SpoolAttribute {
+    pub data_type: String,
+    pub string_value: Option<String>,
+    pub binary_value: Option<Vec<u8>>,
+    pub string_list_values: Option<Vec<String>>,
+    pub binary_list_values: Option<Vec<Vec<u8>>>
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SpoolEntry {
+    pub index: u64,
+    pub message_id: String,
+    pub body: String,
+    pub receipt_handle: String,
+    pub message_attributes: HashMap<String, SpoolAttribute>
+}
+
+#[derive(Serialize, Deserialize)]
+enum SpoolRecord {
+    Put(SpoolEntry),
+    Ack(u64)
+}
+
+struct SpoolState {
+    file: File,
+    next_index: u64,
+    pending: HashMap<u64, SpoolEntry>
+}
+
+/// An append-only, fsync'd write-ahead log of in-flight transfers, replayed
+/// on startup so a crash can't lose a message. File, next_index, and
+/// pending share one lock, so append() and ack()'s rotate can't interleave.
+pub struct Spool {
+    state: Mutex<SpoolState>
+}
+
+impl Spool {
+    pub fn open (dir: &Path) -> Spool {
+        fs::create_dir_all(dir).expect("creating spool directory");
+        let path = dir.join(SEGMENT_FILE_NAME);
+
+        let mut pending = HashMap::new();
+        let mut next_index = 0;
+
+        if let Ok(file) = File::open(&path) {
+            for line in BufReader::new(file).lines() {
+                let line = line.expect("reading spool segment");
+                if line.is_empty() { continue; }
+
+                match serde_json::from_str(&line).expect("parsing spool record") {
+                    SpoolRecord::Put(entry) => {
+                        next_index = std::cmp::max(next_index, entry.index + 1);
+                        pending.insert(entry.index, entry);
+                    },
+                    SpoolRecord::Ack(index) => { pending.remove(&index); }
+                }
+            }
+        }
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .expect("opening spool segment");
+
+        Spool {
+            state: Mutex::new(SpoolState { file, next_index, pending })
+        }
+    }
+
+    /// Entries left over from a previous run that were never acknowledged,
+    /// in the order they were originally appended.
+    pub fn pending_entries (&self) -> Vec<SpoolEntry> {
+        let state = self.state.lock().unwrap();
+        let mut entries: Vec<SpoolEntry> = state.pending.values().cloned().collect();
+        entries.sort_by_key(|entry| entry.index);
+        entries
+    }
+
+    pub fn append (&self, message: &Message) -> u64 {
+        let mut state = self.state.lock().unwrap();
+
+        let index = state.next_index;
+        state.next_index += 1;
+
+        let entry = SpoolEntry {
+            index: index,
+            message_id: message.message_id.to_owned().expect("getting message id"),
+            body: message.body.to_owned().expect("getting body"),
+            receipt_handle: message.receipt_handle.to_owned().expect("getting receipt handle"),
+            message_attributes: to_spool_attributes(&message.message_attributes)
+        };
+
+        write_record(&mut state.file, &SpoolRecord::Put(entry.clone()));
+        state.pending.insert(index, entry);
+
+        index
+    }
+
+    pub fn ack (&self, index: u64) {
+        let mut state = self.state.lock().unwrap();
+
+        write_record(&mut state.file, &SpoolRecord::Ack(index));
+        state.pending.remove(&index);
+
+        // Nothing left to replay, so start the segment over.
+        if state.pending.is_empty() {
+            rotate(&mut state.file, &mut state.next_index);
+        }
+    }
+}
+
+fn write_record (file: &mut File, record: &SpoolRecord) {
+    let line = serde_json::to_string(record).expect("serializing spool record");
+    writeln!(file, "{}", line).expect("appending to spool segment");
+    file.sync_all().expect("fsyncing spool segment");
+}
+
+fn rotate (file: &mut File, next_index: &mut u64) {
+    file.set_len(0).expect("truncating spool segment");
+    file.seek(SeekFrom::Start(0)).expect("seeking spool segment");
+    *next_index = 0;
+}
+
+pub fn to_spool_attributes (attributes: &Option<HashMap<String, MessageAttributeValue>>) -> HashMap<String, SpoolAttribute> {
+    attributes.as_ref().map(|attributes| attributes.iter().map(|(name, value)|
+        (name.to_owned(), SpoolAttribute {
+            data_type: value.data_type.to_owned(),
+            string_value: value.string_value.to_owned(),
+            binary_value: value.binary_value.as_ref().map(|bytes| bytes.to_vec()),
+            string_list_values: value.string_list_values.to_owned(),
+            binary_list_values: value.binary_list_values.as_ref().map(|list|
+                list.iter().map(|bytes| bytes.to_vec()).collect()
+            )
+        })
+    ).collect()).unwrap_or_default()
+}
+
+pub fn from_spool_attributes (attributes: &HashMap<String, SpoolAttribute>) -> HashMap<String, MessageAttributeValue> {
+    attributes.iter().map(|(name, value)| {
+        let mut attribute = MessageAttributeValue::default();
+        attribute.data_type = value.data_type.to_owned();
+        attribute.string_value = value.string_value.to_owned();
+        attribute.binary_value = value.binary_value.to_owned().map(|bytes| bytes.into());
+        attribute.string_list_values = value.string_list_values.to_owned();
+        attribute.binary_list_values = value.binary_list_values.to_owned().map(|list|
+            list.into_iter().map(|bytes| bytes.into()).collect()
+        );
+        (name.to_owned(), attribute)
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_binary_attributes_through_the_spool () {
+        let mut attributes = HashMap::new();
+        attributes.insert("payload".to_owned(), MessageAttributeValue {
+            data_type: "Binary".to_owned(),
+            binary_value: Some(vec!(1, 2, 3).into()),
+            binary_list_values: Some(vec!(vec!(4, 5).into())),
+            string_value: None,
+            string_list_values: None
+        });
+
+        let spooled = to_spool_attributes(&Some(attributes));
+        let restored = from_spool_attributes(&spooled);
+        let attribute = restored.get("payload").expect("getting restored attribute");
+
+        assert_eq!(attribute.binary_value.as_deref(), Some(&[1, 2, 3][..]));
+        assert_eq!(attribute.binary_list_values.as_ref().map(|list| list.len()), Some(1));
+    }
+}
+
+pub fn to_message (entry: &SpoolEntry) -> Message {
+    let mut message = Message::default();
+    message.body = Some(entry.body.to_owned());
+    message.message_attributes = Some(from_spool_attributes(&entry.message_attributes));
+    message
+}